@@ -6,17 +6,24 @@ use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use actix_ws::Message;
 use futures_util::StreamExt;
 use log::info;
+use prometheus::{Encoder, Registry, TextEncoder};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use uuid::Uuid;
 
+mod metrics;
 mod room;
 mod protocol;
 
+use metrics::Metrics;
 use room::RoomManager;
 use protocol::{ClientMessage, ServerMessage, UserInfo};
 
 /// Application state shared across all handlers
 pub struct AppState {
     pub room_manager: RoomManager,
+    pub registry: Registry,
 }
 
 /// Health check endpoint
@@ -28,6 +35,19 @@ async fn health_check() -> HttpResponse {
     }))
 }
 
+/// Prometheus metrics endpoint
+async fn metrics_handler(state: web::Data<AppState>) -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let metric_families = state.registry.gather();
+    let mut buffer = Vec::new();
+    if encoder.encode(&metric_families, &mut buffer).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
+
 /// WebSocket upgrade handler for room connections
 async fn ws_handler(
     req: HttpRequest,
@@ -35,69 +55,206 @@ async fn ws_handler(
     path: web::Path<String>,
     state: web::Data<AppState>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    if !state.room_manager.is_accepting() {
+        return Ok(HttpResponse::ServiceUnavailable().finish());
+    }
+
     let room_id = path.into_inner();
     let user_id = Uuid::new_v4().to_string();
-    
+
     info!("New WebSocket connection: user={} room={}", user_id, room_id);
-    
+
     let (response, mut session, mut msg_stream) = actix_ws::handle(&req, body)?;
-    
-    // Join the room and get a broadcast receiver
-    let (tx, mut rx) = state.room_manager.join_room(&room_id, &user_id).await;
-    
+
+    // Join the room and get a broadcast receiver plus a signal for forced kicks
+    let (tx, mut rx, mut kick_rx, own_user) = state.room_manager.join_room(&room_id, &user_id).await;
+
+    // The joining connection's own UserJoined broadcast goes out before its
+    // receiver subscribes, so tell it directly about its server-assigned id/color/role.
+    let own_user_msg = serde_json::to_string(&ServerMessage::UserJoined { user: own_user }).unwrap_or_default();
+    if session.text(own_user_msg).await.is_err() {
+        state.room_manager.leave_room(&room_id, &user_id).await;
+        return Ok(response);
+    }
+
     // Clone session for the receiver task before moving into async blocks
     let mut session_for_receiver = session.clone();
-    
+
     // Spawn task to handle incoming messages from this client
     let room_id_clone = room_id.clone();
     let user_id_clone = user_id.clone();
     let tx_clone = tx.clone();
-    
-    actix_rt::spawn(async move {
-        while let Some(Ok(msg)) = msg_stream.next().await {
-            match msg {
-                Message::Text(text) => {
-                    // Parse and broadcast the message
-                    if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
-                        let server_msg = ServerMessage::from_client_message(
-                            client_msg,
-                            &user_id_clone,
-                        );
-                        let _ = tx_clone.send(server_msg);
-                    }
-                }
-                Message::Binary(bin) => {
-                    // For Yjs binary sync messages - relay directly
-                    let server_msg = ServerMessage::YjsSync {
-                        user_id: user_id_clone.clone(),
-                        data: bin.to_vec(),
-                    };
-                    let _ = tx_clone.send(server_msg);
-                }
-                Message::Ping(bytes) => {
-                    if session.pong(&bytes).await.is_err() {
-                        break;
+    let state_for_receiver = state.clone();
+
+    let receiver_task = actix_rt::spawn(async move {
+        loop {
+            tokio::select! {
+                maybe_msg = msg_stream.next() => {
+                    let Some(Ok(msg)) = maybe_msg else { break };
+                    match msg {
+                        Message::Text(text) => {
+                            // Parse and handle the message
+                            if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
+                                match client_msg {
+                                    ClientMessage::Draw { operation, base_revision } => {
+                                        let committed = state_for_receiver
+                                            .room_manager
+                                            .apply_draw(&room_id_clone, operation, base_revision)
+                                            .await;
+                                        if let Some((operation, revision)) = committed {
+                                            let server_msg = ServerMessage::DrawUpdate {
+                                                user_id: user_id_clone.clone(),
+                                                operation,
+                                                revision,
+                                            };
+                                            // Fan out to everyone else via the broadcast
+                                            // channel, and tell the originator directly -
+                                            // its own sender task filters this message out
+                                            // as a self-echo, but it still needs the
+                                            // authoritative revision and the (possibly
+                                            // transformed) operation to stay converged.
+                                            let _ = tx_clone.send(server_msg.clone());
+                                            let json = serde_json::to_string(&server_msg).unwrap_or_default();
+                                            if session.text(json).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    ClientMessage::SyncRequest => {
+                                        let (users, operations, revision) = state_for_receiver
+                                            .room_manager
+                                            .sync_room(&room_id_clone)
+                                            .await;
+                                        let room_state = serde_json::to_string(
+                                            &ServerMessage::RoomState { users, revision },
+                                        )
+                                        .unwrap_or_default();
+                                        let snapshot = serde_json::to_string(
+                                            &ServerMessage::CanvasSnapshot { operations, revision },
+                                        )
+                                        .unwrap_or_default();
+                                        if session.text(room_state).await.is_err() {
+                                            break;
+                                        }
+                                        if session.text(snapshot).await.is_err() {
+                                            break;
+                                        }
+                                    }
+                                    ClientMessage::Kick { user_id: target_id } => {
+                                        if let Err(err) = state_for_receiver
+                                            .room_manager
+                                            .kick_user(&room_id_clone, &user_id_clone, &target_id)
+                                            .await
+                                        {
+                                            let error_msg = serde_json::to_string(
+                                                &ServerMessage::Error { message: err.message().to_string() },
+                                            )
+                                            .unwrap_or_default();
+                                            if session.text(error_msg).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    ClientMessage::UpdateRoomMeta { name, topic } => {
+                                        if let Err(err) = state_for_receiver
+                                            .room_manager
+                                            .update_room_meta(&room_id_clone, &user_id_clone, name, topic)
+                                            .await
+                                        {
+                                            let error_msg = serde_json::to_string(
+                                                &ServerMessage::Error { message: err.message().to_string() },
+                                            )
+                                            .unwrap_or_default();
+                                            if session.text(error_msg).await.is_err() {
+                                                break;
+                                            }
+                                        }
+                                    }
+                                    ClientMessage::CursorMove { x, y } => {
+                                        let server_msg = ServerMessage::CursorUpdate {
+                                            user_id: user_id_clone.clone(),
+                                            x,
+                                            y,
+                                        };
+                                        let _ = tx_clone.send(server_msg);
+                                    }
+                                    ClientMessage::UpdateName { name } => {
+                                        let updated = state_for_receiver
+                                            .room_manager
+                                            .update_name(&room_id_clone, &user_id_clone, name)
+                                            .await;
+                                        if let Some(user) = updated {
+                                            let _ = tx_clone.send(ServerMessage::UserUpdated { user });
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Message::Binary(bin) => {
+                            // For Yjs binary sync messages - relay directly
+                            state_for_receiver
+                                .room_manager
+                                .record_yjs_bytes(bin.len() as u64);
+                            let server_msg = ServerMessage::YjsSync {
+                                user_id: user_id_clone.clone(),
+                                data: bin.to_vec(),
+                            };
+                            let _ = tx_clone.send(server_msg);
+                        }
+                        Message::Ping(bytes) => {
+                            if session.pong(&bytes).await.is_err() {
+                                break;
+                            }
+                        }
+                        Message::Close(_) => {
+                            info!("Client {} disconnected from room {}", user_id_clone, room_id_clone);
+                            break;
+                        }
+                        _ => {}
                     }
                 }
-                Message::Close(_) => {
-                    info!("Client {} disconnected from room {}", user_id_clone, room_id_clone);
+                _ = &mut kick_rx => {
+                    info!("Client {} was kicked from room {}", user_id_clone, room_id_clone);
+                    let _ = session.close(None).await;
                     break;
                 }
-                _ => {}
             }
         }
+
+        // Connection ended (client closed, dropped, errored, or kicked) -
+        // leave the room so its user/room gauges and broadcasts stay accurate.
+        state_for_receiver
+            .room_manager
+            .leave_room(&room_id_clone, &user_id_clone)
+            .await;
     });
-    
-    // Spawn task to send broadcast messages to this client
-    actix_rt::spawn(async move {
+
+    // Spawn task to send broadcast messages to this client.
+    //
+    // Invariant: initiators apply their own operations locally, so updates
+    // are fanned out to every *other* participant and never echoed back to
+    // the connection that caused them.
+    let user_id_for_sender = user_id.clone();
+    let sender_task = actix_rt::spawn(async move {
         while let Ok(msg) = rx.recv().await {
+            if msg.origin_user_id() == Some(user_id_for_sender.as_str()) {
+                continue;
+            }
+            let is_shutdown = matches!(msg, ServerMessage::ServerShutdown);
             let json = serde_json::to_string(&msg).unwrap_or_default();
             if session_for_receiver.text(json).await.is_err() {
                 break;
             }
+            if is_shutdown {
+                let _ = session_for_receiver.close(None).await;
+                break;
+            }
         }
     });
-    
+
+    state.room_manager.register_task(receiver_task).await;
+    state.room_manager.register_task(sender_task).await;
+
     Ok(response)
 }
 
@@ -112,24 +269,57 @@ async fn main() -> std::io::Result<()> {
     info!("🚀 Synapse server starting on {}", addr);
     
     // Create shared application state
+    let registry = Registry::new();
+    let metrics = Arc::new(Metrics::new(&registry));
     let app_state = web::Data::new(AppState {
-        room_manager: RoomManager::new(),
+        room_manager: RoomManager::new(metrics),
+        registry,
     });
-    
-    HttpServer::new(move || {
+
+    let shutdown_state = app_state.clone();
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
             .max_age(3600);
-        
+
         App::new()
             .wrap(cors)
             .app_data(app_state.clone())
             .route("/health", web::get().to(health_check))
+            .route("/metrics", web::get().to(metrics_handler))
             .route("/ws/{room_id}", web::get().to(ws_handler))
     })
     .bind(&addr)?
-    .run()
-    .await
+    .run();
+
+    // Drain in-flight rooms and WebSocket tasks on SIGTERM instead of
+    // dropping them abruptly.
+    let server_handle = server.handle();
+    let shutdown_task = actix_rt::spawn(async move {
+        let mut sigterm = signal(SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        sigterm.recv().await;
+        info!("Received SIGTERM, starting graceful shutdown");
+
+        shutdown_state.room_manager.stop_accepting();
+        shutdown_state.room_manager.broadcast_shutdown();
+
+        // Stop accepting new connections; let in-flight HTTP requests finish
+        server_handle.stop(true).await;
+
+        shutdown_state
+            .room_manager
+            .drain(Duration::from_secs(10))
+            .await;
+    });
+
+    // `server.await` resolves as soon as `server_handle.stop(true)` finishes,
+    // which races the drain window above - wait for the shutdown task itself
+    // so `main` doesn't exit (and tear down the runtime) before it completes.
+    server.await?;
+    let _ = shutdown_task.await;
+    Ok(())
 }
@@ -0,0 +1,58 @@
+//! Prometheus metrics for room and connection observability
+
+use prometheus::{IntCounter, IntGauge, Registry};
+
+/// Metrics maintained by the `RoomManager` and exposed via `/metrics`
+pub struct Metrics {
+    /// Number of currently active rooms
+    pub active_rooms: IntGauge,
+    /// Number of users currently connected across all rooms
+    pub connected_users: IntGauge,
+    /// Total draw operations relayed since startup
+    pub draw_operations_total: IntCounter,
+    /// Total bytes relayed over the Yjs binary sync path since startup
+    pub yjs_bytes_relayed_total: IntCounter,
+}
+
+impl Metrics {
+    /// Construct the metrics and register them with `registry`
+    pub fn new(registry: &Registry) -> Self {
+        let active_rooms =
+            IntGauge::new("synapse_active_rooms", "Number of currently active rooms").unwrap();
+        let connected_users = IntGauge::new(
+            "synapse_connected_users",
+            "Number of users currently connected across all rooms",
+        )
+        .unwrap();
+        let draw_operations_total = IntCounter::new(
+            "synapse_draw_operations_total",
+            "Total draw operations relayed since startup",
+        )
+        .unwrap();
+        let yjs_bytes_relayed_total = IntCounter::new(
+            "synapse_yjs_bytes_relayed_total",
+            "Total bytes relayed over the Yjs binary sync path since startup",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(active_rooms.clone()))
+            .expect("failed to register synapse_active_rooms");
+        registry
+            .register(Box::new(connected_users.clone()))
+            .expect("failed to register synapse_connected_users");
+        registry
+            .register(Box::new(draw_operations_total.clone()))
+            .expect("failed to register synapse_draw_operations_total");
+        registry
+            .register(Box::new(yjs_bytes_relayed_total.clone()))
+            .expect("failed to register synapse_yjs_bytes_relayed_total");
+
+        Self {
+            active_rooms,
+            connected_users,
+            draw_operations_total,
+            yjs_bytes_relayed_total,
+        }
+    }
+}
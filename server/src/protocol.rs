@@ -8,6 +8,15 @@ pub struct UserInfo {
     pub id: String,
     pub name: String,
     pub color: String,
+    pub role: UserRole,
+}
+
+/// A user's moderation role within a room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UserRole {
+    /// The first user to create the room; can kick and update room metadata
+    Owner,
+    Member,
 }
 
 /// Cursor position for a user
@@ -24,14 +33,25 @@ pub enum ClientMessage {
     /// Update cursor position
     CursorMove { x: f64, y: f64 },
     
-    /// Drawing operation (strokes, shapes, etc.)
-    Draw { operation: DrawOperation },
+    /// Drawing operation (strokes, shapes, etc.), stamped with the room
+    /// revision the client had applied it against so the server can
+    /// transform it against anything committed since then
+    Draw {
+        operation: DrawOperation,
+        base_revision: u64,
+    },
     
     /// Request current room state
     SyncRequest,
     
     /// User updated their name
     UpdateName { name: String },
+
+    /// Forcibly remove a user from the room; only honored from the room owner
+    Kick { user_id: String },
+
+    /// Update room-level metadata; only honored from the room owner
+    UpdateRoomMeta { name: String, topic: String },
 }
 
 /// Drawing operations on the canvas
@@ -80,6 +100,20 @@ pub enum DrawOperation {
     Clear,
 }
 
+impl DrawOperation {
+    /// The id of the object this operation targets, if any (`Clear` has none)
+    pub fn target_id(&self) -> Option<&str> {
+        match self {
+            DrawOperation::PathStart { id, .. }
+            | DrawOperation::PathPoint { id, .. }
+            | DrawOperation::PathEnd { id }
+            | DrawOperation::Shape { id, .. }
+            | DrawOperation::Erase { id } => Some(id),
+            DrawOperation::Clear => None,
+        }
+    }
+}
+
 /// Types of shapes that can be drawn
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ShapeType {
@@ -95,7 +129,10 @@ pub enum ShapeType {
 pub enum ServerMessage {
     /// A user joined the room
     UserJoined { user: UserInfo },
-    
+
+    /// A user's presence info (e.g. name) was updated
+    UserUpdated { user: UserInfo },
+
     /// A user left the room
     UserLeft { user_id: String },
     
@@ -106,51 +143,60 @@ pub enum ServerMessage {
         y: f64,
     },
     
-    /// Drawing operation from another user
+    /// Committed drawing operation (also sent back to its originator),
+    /// already transformed against anything committed ahead of it, carrying
+    /// the revision it landed at
     DrawUpdate {
         user_id: String,
         operation: DrawOperation,
+        revision: u64,
     },
-    
+
     /// Full room state sync
     RoomState {
         users: Vec<UserInfo>,
-        // Canvas state would be included here
+        revision: u64,
     },
-    
+
+    /// Accumulated canvas operations, sent to a client in response to `SyncRequest`
+    CanvasSnapshot {
+        operations: Vec<DrawOperation>,
+        revision: u64,
+    },
+
     /// Yjs binary sync data (for CRDT state)
     YjsSync {
         user_id: String,
         data: Vec<u8>,
     },
-    
+
+    /// Room-level metadata was updated by the owner
+    RoomMeta { name: String, topic: String },
+
+    /// The server is shutting down; clients should persist state locally
+    ServerShutdown,
+
     /// Error message
     Error { message: String },
 }
 
 impl ServerMessage {
-    /// Convert a client message to a server broadcast message
-    pub fn from_client_message(msg: ClientMessage, user_id: &str) -> Self {
-        match msg {
-            ClientMessage::CursorMove { x, y } => ServerMessage::CursorUpdate {
-                user_id: user_id.to_string(),
-                x,
-                y,
-            },
-            ClientMessage::Draw { operation } => ServerMessage::DrawUpdate {
-                user_id: user_id.to_string(),
-                operation,
-            },
-            ClientMessage::SyncRequest => ServerMessage::RoomState {
-                users: vec![], // Would be populated with actual users
-            },
-            ClientMessage::UpdateName { name } => ServerMessage::UserJoined {
-                user: UserInfo {
-                    id: user_id.to_string(),
-                    name,
-                    color: String::new(), // Would use actual color
-                },
-            },
+    /// The user who caused this message, if any. Used to skip re-delivering a
+    /// broadcast update to the connection that originated it, since that client
+    /// already applied the change locally.
+    pub fn origin_user_id(&self) -> Option<&str> {
+        match self {
+            ServerMessage::UserJoined { user } => Some(&user.id),
+            ServerMessage::UserUpdated { user } => Some(&user.id),
+            ServerMessage::UserLeft { user_id } => Some(user_id),
+            ServerMessage::CursorUpdate { user_id, .. } => Some(user_id),
+            ServerMessage::DrawUpdate { user_id, .. } => Some(user_id),
+            ServerMessage::YjsSync { user_id, .. } => Some(user_id),
+            ServerMessage::RoomState { .. }
+            | ServerMessage::CanvasSnapshot { .. }
+            | ServerMessage::RoomMeta { .. }
+            | ServerMessage::ServerShutdown
+            | ServerMessage::Error { .. } => None,
         }
     }
 }
@@ -1,15 +1,49 @@
 //! Room management for collaborative sessions
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use std::time::Duration;
+use dashmap::DashMap;
+use tokio::sync::{broadcast, oneshot, Mutex};
+use tokio::task::JoinHandle;
 use log::info;
 
-use crate::protocol::{ServerMessage, UserInfo};
+use crate::metrics::Metrics;
+use crate::protocol::{DrawOperation, ServerMessage, UserInfo, UserRole};
 
-/// Manages all active rooms and their participants
+/// Why a `kick_user` or `update_room_meta` request couldn't be honored
+#[derive(Debug, PartialEq, Eq)]
+pub enum ModerationError {
+    RoomNotFound,
+    TargetNotFound,
+    RequesterNotOwner,
+}
+
+impl ModerationError {
+    /// A message suitable for the requester's own `ServerMessage::Error`
+    pub fn message(&self) -> &'static str {
+        match self {
+            ModerationError::RoomNotFound => "Room not found",
+            ModerationError::TargetNotFound => "User not found in room",
+            ModerationError::RequesterNotOwner => "Only the room owner can do that",
+        }
+    }
+}
+
+/// Manages all active rooms and their participants.
+///
+/// Rooms are sharded in a `DashMap` rather than held behind a single global
+/// lock, so `join_room`/`leave_room` calls for different rooms proceed
+/// concurrently instead of contending on one lock for the whole server.
 pub struct RoomManager {
-    rooms: Arc<RwLock<HashMap<String, Room>>>,
+    rooms: Arc<DashMap<String, Room>>,
+    metrics: Arc<Metrics>,
+    /// Whether new `/ws` upgrades should still be accepted, cleared on shutdown
+    accepting: AtomicBool,
+    /// Per-connection sender/receiver tasks, awaited during graceful shutdown
+    /// so they get a chance to drain before the process exits
+    connection_tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
 /// A single collaborative room
@@ -18,6 +52,18 @@ struct Room {
     tx: broadcast::Sender<ServerMessage>,
     /// Connected users in this room
     users: HashMap<String, UserInfo>,
+    /// Authoritative canvas state, replayed to late joiners on `SyncRequest`
+    canvas: CanvasStore,
+    /// Monotonically increasing revision, bumped once per committed draw operation
+    revision: u64,
+    /// Committed draw operations tagged with the revision they landed at,
+    /// used to transform incoming ops against whatever landed ahead of them
+    op_log: Vec<CommittedOp>,
+    /// Room-level metadata set via `UpdateRoomMeta`
+    meta: RoomMeta,
+    /// Per-connection close signals, so an owner's `Kick` can terminate a
+    /// target's session rather than just dropping them from the user map
+    kick_signals: HashMap<String, oneshot::Sender<()>>,
 }
 
 impl Room {
@@ -26,76 +72,361 @@ impl Room {
         Self {
             tx,
             users: HashMap::new(),
+            canvas: CanvasStore::new(),
+            revision: 0,
+            op_log: Vec::new(),
+            meta: RoomMeta::default(),
+            kick_signals: HashMap::new(),
+        }
+    }
+}
+
+/// Room-level metadata set via `UpdateRoomMeta`
+#[derive(Default, Clone)]
+struct RoomMeta {
+    name: String,
+    topic: String,
+}
+
+/// A draw operation as committed to a room's log
+struct CommittedOp {
+    revision: u64,
+    operation: DrawOperation,
+}
+
+/// Transform an incoming draw operation against everything committed to
+/// `log` since `base_revision`, per the whiteboard's per-object OT rules:
+/// creates with distinct ids commute; a `Clear` supersedes any pending op
+/// with a lower revision; and an `Erase` wins over a later edit (including
+/// another `Erase`) on the same id. In both superseded cases the incoming op
+/// becomes a no-op, returned as `None` so the caller drops it rather than
+/// committing a synthetic `Clear`/`Erase` in its place.
+fn transform_against_log(op: DrawOperation, base_revision: u64, log: &[CommittedOp]) -> Option<DrawOperation> {
+    let target = op.target_id().map(str::to_string);
+
+    for committed in log.iter().filter(|c| c.revision > base_revision) {
+        match &committed.operation {
+            DrawOperation::Clear => return None,
+            DrawOperation::Erase { id } if Some(id.as_str()) == target.as_deref() => {
+                return None;
+            }
+            _ => {}
+        }
+    }
+
+    Some(op)
+}
+
+/// Ordered, authoritative store of the draw operations that make up a room's canvas
+#[derive(Default)]
+struct CanvasStore {
+    /// Operations that built up each object, keyed by `DrawOperation::target_id`
+    ops_by_id: HashMap<String, Vec<DrawOperation>>,
+    /// Z-order in which objects were first drawn
+    order: Vec<String>,
+}
+
+impl CanvasStore {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply an inbound draw operation to the store, mutating shapes/paths in place
+    fn apply(&mut self, op: DrawOperation) {
+        match &op {
+            DrawOperation::Erase { id } => {
+                self.ops_by_id.remove(id);
+                self.order.retain(|existing| existing != id);
+                return;
+            }
+            DrawOperation::Clear => {
+                self.ops_by_id.clear();
+                self.order.clear();
+                return;
+            }
+            _ => {}
         }
+
+        if let Some(id) = op.target_id() {
+            if !self.ops_by_id.contains_key(id) {
+                self.order.push(id.to_string());
+            }
+            self.ops_by_id.entry(id.to_string()).or_default().push(op);
+        }
+    }
+
+    /// Replay the accumulated operations in z-order, e.g. for a late-joining client
+    fn snapshot(&self) -> Vec<DrawOperation> {
+        self.order
+            .iter()
+            .filter_map(|id| self.ops_by_id.get(id))
+            .flat_map(|ops| ops.iter().cloned())
+            .collect()
     }
 }
 
 impl RoomManager {
-    pub fn new() -> Self {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
         Self {
-            rooms: Arc::new(RwLock::new(HashMap::new())),
+            rooms: Arc::new(DashMap::new()),
+            metrics,
+            accepting: AtomicBool::new(true),
+            connection_tasks: Mutex::new(Vec::new()),
         }
     }
-    
-    /// Join a room, creating it if it doesn't exist
-    /// Returns the broadcast sender and receiver for the room
+
+    /// Whether new `/ws` upgrades should still be accepted
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting new `/ws` upgrades ahead of a graceful shutdown
+    pub fn stop_accepting(&self) {
+        self.accepting.store(false, Ordering::SeqCst);
+    }
+
+    /// Register a connection's spawned tasks so shutdown can wait for them to drain
+    pub async fn register_task(&self, handle: JoinHandle<()>) {
+        self.connection_tasks.lock().await.push(handle);
+    }
+
+    /// Broadcast a `ServerShutdown` notice to every room so clients can
+    /// persist state locally before their connection is closed
+    pub fn broadcast_shutdown(&self) {
+        for room in self.rooms.iter() {
+            let _ = room.tx.send(ServerMessage::ServerShutdown);
+        }
+    }
+
+    /// Wait up to `timeout` for all registered connection tasks to finish,
+    /// giving them a bounded window to flush queued messages and close
+    /// their sessions cleanly.
+    pub async fn drain(&self, timeout: Duration) {
+        let handles = std::mem::take(&mut *self.connection_tasks.lock().await);
+        if tokio::time::timeout(timeout, futures_util::future::join_all(handles))
+            .await
+            .is_err()
+        {
+            info!("Graceful shutdown drain window elapsed with tasks still running");
+        }
+    }
+
+    /// Join a room, creating it if it doesn't exist. The first user to
+    /// create a room becomes its owner. Returns the broadcast sender and
+    /// receiver for the room, a oneshot receiver the caller should select on
+    /// to learn when it has been kicked, and the joining user's own
+    /// `UserInfo` — since the `UserJoined` broadcast goes out before the
+    /// returned receiver subscribes, this is the only place the caller learns its
+    /// server-assigned id/color/role.
     pub async fn join_room(
         &self,
         room_id: &str,
         user_id: &str,
-    ) -> (broadcast::Sender<ServerMessage>, broadcast::Receiver<ServerMessage>) {
-        let mut rooms = self.rooms.write().await;
-        
-        let room = rooms.entry(room_id.to_string()).or_insert_with(|| {
+    ) -> (
+        broadcast::Sender<ServerMessage>,
+        broadcast::Receiver<ServerMessage>,
+        oneshot::Receiver<()>,
+        UserInfo,
+    ) {
+        // Check-then-insert on the entry itself (rather than a separate
+        // `contains_key` probe) so the "was this room just created" decision
+        // and the insert happen under the same shard lock — two connections
+        // racing to join a brand-new room can't both see themselves as the
+        // creator and both be handed `UserRole::Owner`.
+        let entry = self.rooms.entry(room_id.to_string());
+        let is_new_room = matches!(&entry, dashmap::mapref::entry::Entry::Vacant(_));
+        let mut room = entry.or_insert_with(|| {
             info!("Creating new room: {}", room_id);
             Room::new()
         });
-        
+        if is_new_room {
+            self.metrics.active_rooms.inc();
+        }
+
         // Add user to room
+        let role = if is_new_room {
+            UserRole::Owner
+        } else {
+            UserRole::Member
+        };
         let user_info = UserInfo {
             id: user_id.to_string(),
             name: format!("User {}", &user_id[..8]),
             color: generate_user_color(user_id),
+            role,
         };
         room.users.insert(user_id.to_string(), user_info.clone());
-        
-        // Broadcast user joined
-        let _ = room.tx.send(ServerMessage::UserJoined { user: user_info });
-        
+        self.metrics.connected_users.inc();
+
+        let (kick_tx, kick_rx) = oneshot::channel();
+        room.kick_signals.insert(user_id.to_string(), kick_tx);
+
+        // Broadcast user joined (sent before the joining connection's own
+        // receiver is subscribed below, so that connection never gets it; it
+        // learns its `UserInfo` from the return value instead)
+        let _ = room.tx.send(ServerMessage::UserJoined { user: user_info.clone() });
+
         let tx = room.tx.clone();
         let rx = room.tx.subscribe();
-        
-        (tx, rx)
+
+        (tx, rx, kick_rx, user_info)
     }
-    
+
     /// Remove a user from a room
     pub async fn leave_room(&self, room_id: &str, user_id: &str) {
-        let mut rooms = self.rooms.write().await;
-        
-        if let Some(room) = rooms.get_mut(room_id) {
-            room.users.remove(user_id);
-            
-            // Broadcast user left
-            let _ = room.tx.send(ServerMessage::UserLeft {
-                user_id: user_id.to_string(),
-            });
-            
-            // Clean up empty rooms
-            if room.users.is_empty() {
-                info!("Removing empty room: {}", room_id);
-                rooms.remove(room_id);
+        let should_remove_room = if let Some(mut room) = self.rooms.get_mut(room_id) {
+            let was_present = room.users.remove(user_id).is_some();
+            room.kick_signals.remove(user_id);
+
+            if was_present {
+                self.metrics.connected_users.dec();
+                // Broadcast user left. Guarded on `was_present` so a kicked
+                // user - whose removal and `UserLeft` broadcast already
+                // happened in `kick_user` - doesn't generate a second,
+                // phantom departure once their connection task notices the
+                // kick and calls back in here.
+                let _ = room.tx.send(ServerMessage::UserLeft {
+                    user_id: user_id.to_string(),
+                });
             }
+
+            room.users.is_empty()
+        } else {
+            false
+        };
+
+        // Clean up empty rooms
+        if should_remove_room {
+            info!("Removing empty room: {}", room_id);
+            self.rooms.remove(room_id);
+            self.metrics.active_rooms.dec();
         }
     }
-    
+
+    /// Update a user's display name in place, preserving their assigned
+    /// color and role, and return the updated `UserInfo` to broadcast
+    pub async fn update_name(&self, room_id: &str, user_id: &str, name: String) -> Option<UserInfo> {
+        let mut room = self.rooms.get_mut(room_id)?;
+        let user = room.users.get_mut(user_id)?;
+        user.name = name;
+        Some(user.clone())
+    }
+
+    /// Forcibly remove `target_id` from a room on behalf of `requester_id`,
+    /// who must be the room's owner. Signals the target's connection to
+    /// close via its registered kick handle.
+    pub async fn kick_user(
+        &self,
+        room_id: &str,
+        requester_id: &str,
+        target_id: &str,
+    ) -> Result<(), ModerationError> {
+        let mut room = self.rooms.get_mut(room_id).ok_or(ModerationError::RoomNotFound)?;
+
+        let requester_is_owner = room
+            .users
+            .get(requester_id)
+            .map(|u| u.role == UserRole::Owner)
+            .unwrap_or(false);
+        if !requester_is_owner {
+            return Err(ModerationError::RequesterNotOwner);
+        }
+
+        if room.users.remove(target_id).is_none() {
+            return Err(ModerationError::TargetNotFound);
+        }
+        self.metrics.connected_users.dec();
+
+        let _ = room.tx.send(ServerMessage::UserLeft {
+            user_id: target_id.to_string(),
+        });
+
+        if let Some(signal) = room.kick_signals.remove(target_id) {
+            let _ = signal.send(());
+        }
+
+        Ok(())
+    }
+
+    /// Update a room's name/topic on behalf of `requester_id`, who must be
+    /// the room's owner, broadcasting the new metadata to all participants
+    pub async fn update_room_meta(
+        &self,
+        room_id: &str,
+        requester_id: &str,
+        name: String,
+        topic: String,
+    ) -> Result<(), ModerationError> {
+        let mut room = self.rooms.get_mut(room_id).ok_or(ModerationError::RoomNotFound)?;
+
+        let requester_is_owner = room
+            .users
+            .get(requester_id)
+            .map(|u| u.role == UserRole::Owner)
+            .unwrap_or(false);
+        if !requester_is_owner {
+            return Err(ModerationError::RequesterNotOwner);
+        }
+
+        room.meta = RoomMeta {
+            name: name.clone(),
+            topic: topic.clone(),
+        };
+        let _ = room.tx.send(ServerMessage::RoomMeta { name, topic });
+
+        Ok(())
+    }
+
     /// Get all users in a room
     pub async fn get_room_users(&self, room_id: &str) -> Vec<UserInfo> {
-        let rooms = self.rooms.read().await;
-        rooms
+        self.rooms
             .get(room_id)
             .map(|r| r.users.values().cloned().collect())
             .unwrap_or_default()
     }
+
+    /// Commit an inbound draw operation, transforming it against anything
+    /// committed since `base_revision` before applying it to the canvas
+    /// store. Returns the (possibly transformed) operation to rebroadcast
+    /// along with the room's new revision, or `None` if the room is gone or
+    /// the op was superseded by something already committed (a true no-op
+    /// that isn't itself committed or counted).
+    pub async fn apply_draw(
+        &self,
+        room_id: &str,
+        operation: DrawOperation,
+        base_revision: u64,
+    ) -> Option<(DrawOperation, u64)> {
+        let mut room = self.rooms.get_mut(room_id)?;
+
+        let transformed = transform_against_log(operation, base_revision, &room.op_log)?;
+        room.canvas.apply(transformed.clone());
+
+        room.revision += 1;
+        let revision = room.revision;
+        room.op_log.push(CommittedOp {
+            revision,
+            operation: transformed.clone(),
+        });
+
+        self.metrics.draw_operations_total.inc();
+        Some((transformed, revision))
+    }
+
+    /// Record bytes relayed through the Yjs binary sync path
+    pub fn record_yjs_bytes(&self, bytes: u64) {
+        self.metrics.yjs_bytes_relayed_total.inc_by(bytes);
+    }
+
+    /// Get the current user list, accumulated canvas operations, and current
+    /// revision for a room, used to answer a `SyncRequest` from a single
+    /// (re)joining client so it starts synchronized with the server
+    pub async fn sync_room(&self, room_id: &str) -> (Vec<UserInfo>, Vec<DrawOperation>, u64) {
+        self.rooms
+            .get(room_id)
+            .map(|r| (r.users.values().cloned().collect(), r.canvas.snapshot(), r.revision))
+            .unwrap_or_default()
+    }
 }
 
 /// Generate a consistent color for a user based on their ID
@@ -116,9 +447,3 @@ fn generate_user_color(user_id: &str) -> String {
     let hash: usize = user_id.bytes().map(|b| b as usize).sum();
     colors[hash % colors.len()].to_string()
 }
-
-impl Default for RoomManager {
-    fn default() -> Self {
-        Self::new()
-    }
-}